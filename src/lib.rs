@@ -6,49 +6,410 @@ use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Write};
 // --- Modules ---
 
 pub mod rules {
-    use serde::Deserialize;
+    use serde::{Deserialize, Deserializer};
 
     #[derive(Debug, Clone, Deserialize)]
     pub struct Rule {
         pub keyword: String,
-        pub color: Color,
+        #[serde(alias = "color")]
+        pub style: Style,
         #[serde(default)]
         pub is_regex: bool,
+        /// Color just this one capture group of a regex rule (its whole
+        /// match is still used for `last_match`/line splitting, but only
+        /// the group's span is colored, with the rest left plain).
+        #[serde(default)]
+        pub group: Option<GroupRef>,
+        /// Color several capture groups independently. Takes precedence
+        /// over `group` when both are set.
+        #[serde(default)]
+        pub groups: Vec<GroupStyle>,
+    }
+
+    /// References one capture group of a regex rule, either by its
+    /// 1-based position or by its `(?P<name>...)` name.
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(untagged)]
+    pub enum GroupRef {
+        Index(usize),
+        Name(String),
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct GroupStyle {
+        pub group: GroupRef,
+        pub style: Style,
     }
 
     #[derive(Debug, Copy, Clone, Deserialize)]
     #[serde(tag = "type", rename_all = "PascalCase", content = "value")]
     pub enum PresetColor {
+        Black,
         Red,
         Yellow,
         Blue,
         Green,
         Cyan,
         Magenta,
+        White,
+    }
+
+    impl PresetColor {
+        /// The `N` in the `3N`/`4N` SGR foreground/background codes.
+        fn sgr_offset(&self) -> u8 {
+            match self {
+                PresetColor::Black => 0,
+                PresetColor::Red => 1,
+                PresetColor::Green => 2,
+                PresetColor::Yellow => 3,
+                PresetColor::Blue => 4,
+                PresetColor::Magenta => 5,
+                PresetColor::Cyan => 6,
+                PresetColor::White => 7,
+            }
+        }
     }
 
     #[derive(Debug, Copy, Clone, Deserialize)]
     #[serde(untagged)]
     pub enum Color {
         Preset(PresetColor),
+        Indexed { n: u8 },
         RGB { r: u8, g: u8, b: u8 },
     }
 
     impl Color {
-        pub fn to_ansi(&self) -> String {
+        /// `base` is `3` for foreground, `4` for background, following the
+        /// ANSI convention that background codes are the foreground ones
+        /// plus 10 (`38` -> `48`, `31` -> `41`, ...).
+        fn sgr_param(&self, base: u8) -> String {
             match self {
-                Color::Preset(p) => match p {
-                    PresetColor::Red => "\x1b[31m".to_string(),
-                    PresetColor::Yellow => "\x1b[33m".to_string(),
-                    PresetColor::Blue => "\x1b[34m".to_string(),
-                    PresetColor::Green => "\x1b[32m".to_string(),
-                    PresetColor::Cyan => "\x1b[36m".to_string(),
-                    PresetColor::Magenta => "\x1b[35m".to_string(),
+                Color::Preset(p) => format!("{}{}", base, p.sgr_offset()),
+                Color::Indexed { n } => format!("{};5;{}", base * 10 + 8, n),
+                Color::RGB { r, g, b } => format!("{};2;{};{};{}", base * 10 + 8, r, g, b),
+            }
+        }
+
+        /// Downgrades a richer color to whatever `mode` allows, the way
+        /// hyfetch's `--color-mode` flattens truecolor output for
+        /// terminals that can't render it.
+        pub fn downgraded(&self, mode: ColorMode) -> Color {
+            match (mode, *self) {
+                (ColorMode::Truecolor, c) => c,
+                (ColorMode::Ansi256, Color::RGB { r, g, b }) => Color::Indexed {
+                    n: rgb_to_ansi256(r, g, b),
                 },
-                Color::RGB { r, g, b } => format!("\x1b[38;2;{};{};{}m", r, g, b),
+                (ColorMode::Ansi256, c) => c,
+                (ColorMode::Ansi16, Color::RGB { r, g, b }) => Color::Preset(nearest_preset(r, g, b)),
+                (ColorMode::Ansi16, Color::Indexed { n }) => {
+                    let (r, g, b) = ansi256_to_rgb(n);
+                    Color::Preset(nearest_preset(r, g, b))
+                }
+                (ColorMode::Ansi16, c) => c,
+            }
+        }
+    }
+
+    /// The terminal color capability to downgrade output for, selected via
+    /// `--color-mode` or, when that flag is absent, auto-detected from
+    /// `COLORTERM`/`TERM` by `detect_color_mode`.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Default, clap::ValueEnum)]
+    pub enum ColorMode {
+        #[default]
+        Truecolor,
+        #[value(name = "256")]
+        Ansi256,
+        #[value(name = "16")]
+        Ansi16,
+    }
+
+    /// Picks a default `ColorMode` from the terminal-capability env vars,
+    /// the way most ANSI-aware tools (e.g. `fd`, `ripgrep`) infer color
+    /// support when the user hasn't forced a mode: `COLORTERM` of
+    /// `truecolor`/`24bit` wins outright, a `TERM` ending in `-256color`
+    /// implies 256-color support, and a `TERM` we know is limited to the
+    /// basic palette downgrades to `Ansi16`. Anything else (an unset or
+    /// unrecognized `TERM`, as in most CI environments) is inconclusive,
+    /// and we'd rather over- than under-color, so it's treated as
+    /// `Truecolor` — matching this tool's pre-auto-detect behavior instead
+    /// of silently flattening `RGB` rules down to a handful of presets.
+    pub fn detect_color_mode(colorterm: Option<&str>, term: Option<&str>) -> ColorMode {
+        if let Some(colorterm) = colorterm {
+            if colorterm.eq_ignore_ascii_case("truecolor") || colorterm.eq_ignore_ascii_case("24bit")
+            {
+                return ColorMode::Truecolor;
+            }
+        }
+        if let Some(term) = term {
+            if term.ends_with("256color") {
+                return ColorMode::Ansi256;
+            }
+            if is_basic_color_term(term) {
+                return ColorMode::Ansi16;
+            }
+        }
+        ColorMode::Truecolor
+    }
+
+    /// `TERM` values known to support only the basic 8/16-color SGR set,
+    /// with no richer escape sequences to downgrade from.
+    fn is_basic_color_term(term: &str) -> bool {
+        matches!(term, "dumb" | "linux" | "ansi" | "vt100" | "vt220")
+    }
+
+    /// `detect_color_mode` against the actual process environment.
+    pub fn detect_color_mode_from_env() -> ColorMode {
+        detect_color_mode(
+            std::env::var("COLORTERM").ok().as_deref(),
+            std::env::var("TERM").ok().as_deref(),
+        )
+    }
+
+    /// A rule's full visual style: an optional foreground/background color
+    /// plus text attributes. Config authors can still write the old
+    /// `Preset`/`RGB` forms (taken as the foreground color with no other
+    /// attributes), or an LS_COLORS-style SGR parameter string such as
+    /// `"1;38;5;203;48;5;236"`, mirroring the specs `vivid`/`LS_COLORS`
+    /// produce and that fd/eza already consume.
+    #[derive(Debug, Clone, Default)]
+    pub struct Style {
+        pub fg: Option<Color>,
+        pub bg: Option<Color>,
+        pub bold: bool,
+        pub dim: bool,
+        pub italic: bool,
+        pub underline: bool,
+        pub reverse: bool,
+    }
+
+    impl Style {
+        pub fn to_ansi(&self) -> String {
+            let mut codes = Vec::new();
+            if self.bold {
+                codes.push("1".to_string());
+            }
+            if self.dim {
+                codes.push("2".to_string());
+            }
+            if self.italic {
+                codes.push("3".to_string());
+            }
+            if self.underline {
+                codes.push("4".to_string());
+            }
+            if self.reverse {
+                codes.push("7".to_string());
+            }
+            if let Some(fg) = &self.fg {
+                codes.push(fg.sgr_param(3));
+            }
+            if let Some(bg) = &self.bg {
+                codes.push(bg.sgr_param(4));
+            }
+            format!("\x1b[{}m", codes.join(";"))
+        }
+
+        fn from_sgr_spec(spec: &str) -> Result<Self, String> {
+            let mut style = Style::default();
+            let mut parts = spec.split(';').peekable();
+
+            while let Some(code) = parts.next() {
+                match code {
+                    "0" => style = Style::default(),
+                    "1" => style.bold = true,
+                    "2" => style.dim = true,
+                    "3" => style.italic = true,
+                    "4" => style.underline = true,
+                    "7" => style.reverse = true,
+                    "38" | "48" => {
+                        let is_bg = code == "48";
+                        let color = parse_sgr_color(&mut parts)
+                            .ok_or_else(|| format!("incomplete color in style spec {spec:?}"))?;
+                        if is_bg {
+                            style.bg = Some(color);
+                        } else {
+                            style.fg = Some(color);
+                        }
+                    }
+                    other => return Err(format!("unsupported SGR code {other:?} in {spec:?}")),
+                }
+            }
+
+            Ok(style)
+        }
+
+        /// Downgrades both colors for a limited terminal; attributes like
+        /// bold/underline are unaffected by color mode.
+        pub fn downgraded(&self, mode: ColorMode) -> Style {
+            let mut style = self.clone();
+            style.fg = self.fg.map(|c| c.downgraded(mode));
+            style.bg = self.bg.map(|c| c.downgraded(mode));
+            style
+        }
+    }
+
+    /// Consumes either a `5;N` (256-color) or `2;r;g;b` (truecolor) tail
+    /// after a `38`/`48` code.
+    fn parse_sgr_color<'a>(parts: &mut impl Iterator<Item = &'a str>) -> Option<Color> {
+        match parts.next()? {
+            "5" => {
+                let n: u8 = parts.next()?.parse().ok()?;
+                Some(Color::Indexed { n })
+            }
+            "2" => {
+                let r = parts.next()?.parse().ok()?;
+                let g = parts.next()?.parse().ok()?;
+                let b = parts.next()?.parse().ok()?;
+                Some(Color::RGB { r, g, b })
+            }
+            _ => None,
+        }
+    }
+
+    /// Standard xterm 256-color palette: 0-15 basic/bright, 16-231 a 6x6x6
+    /// color cube, 232-255 a grayscale ramp.
+    pub(crate) fn ansi256_to_rgb(n: u8) -> (u8, u8, u8) {
+        const BASIC: [(u8, u8, u8); 16] = [
+            (0, 0, 0),
+            (128, 0, 0),
+            (0, 128, 0),
+            (128, 128, 0),
+            (0, 0, 128),
+            (128, 0, 128),
+            (0, 128, 128),
+            (192, 192, 192),
+            (128, 128, 128),
+            (255, 0, 0),
+            (0, 255, 0),
+            (255, 255, 0),
+            (0, 0, 255),
+            (255, 0, 255),
+            (0, 255, 255),
+            (255, 255, 255),
+        ];
+
+        if n < 16 {
+            return BASIC[n as usize];
+        }
+        if n >= 232 {
+            let level = 8 + 10 * (n - 232);
+            return (level, level, level);
+        }
+        let idx = n - 16;
+        let component = |c: u8| if c == 0 { 0 } else { 55 + 40 * c };
+        let r = component(idx / 36);
+        let g = component((idx / 6) % 6);
+        let b = component(idx % 6);
+        (r, g, b)
+    }
+
+    /// Quantizes an RGB color into the 6x6x6 color cube of the xterm
+    /// 256-color palette (indices 16-231).
+    fn rgb_to_ansi256(r: u8, g: u8, b: u8) -> u8 {
+        let quantize = |c: u8| -> u8 { ((c as u16 * 5 + 127) / 255) as u8 };
+        16 + 36 * quantize(r) + 6 * quantize(g) + quantize(b)
+    }
+
+    impl PresetColor {
+        /// An approximate RGB value for each preset, used to find the
+        /// nearest preset when downgrading truecolor/256-color output.
+        fn approx_rgb(&self) -> (u8, u8, u8) {
+            match self {
+                PresetColor::Black => (0, 0, 0),
+                PresetColor::Red => (128, 0, 0),
+                PresetColor::Green => (0, 128, 0),
+                PresetColor::Yellow => (128, 128, 0),
+                PresetColor::Blue => (0, 0, 128),
+                PresetColor::Magenta => (128, 0, 128),
+                PresetColor::Cyan => (0, 128, 128),
+                PresetColor::White => (192, 192, 192),
+            }
+        }
+    }
+
+    /// Maps an RGB color to whichever of the 8 basic presets (including
+    /// black/white, not just the 6 hues) is closest, by squared Euclidean
+    /// distance.
+    fn nearest_preset(r: u8, g: u8, b: u8) -> PresetColor {
+        const CANDIDATES: [PresetColor; 8] = [
+            PresetColor::Black,
+            PresetColor::Red,
+            PresetColor::Green,
+            PresetColor::Yellow,
+            PresetColor::Blue,
+            PresetColor::Magenta,
+            PresetColor::Cyan,
+            PresetColor::White,
+        ];
+
+        CANDIDATES
+            .into_iter()
+            .min_by_key(|p| {
+                let (pr, pg, pb) = p.approx_rgb();
+                let dr = r as i32 - pr as i32;
+                let dg = g as i32 - pg as i32;
+                let db = b as i32 - pb as i32;
+                dr * dr + dg * dg + db * db
+            })
+            .unwrap()
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawStyle {
+        Spec(String),
+        Color(Color),
+    }
+
+    impl<'de> Deserialize<'de> for Style {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            match RawStyle::deserialize(deserializer)? {
+                RawStyle::Color(color) => Ok(Style {
+                    fg: Some(color),
+                    ..Style::default()
+                }),
+                RawStyle::Spec(spec) => {
+                    Style::from_sgr_spec(&spec).map_err(serde::de::Error::custom)
+                }
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn from_sgr_spec_parses_combined_indexed_colors() {
+            let style = Style::from_sgr_spec("1;38;5;203;48;5;236").unwrap();
+            assert_eq!(style.to_ansi(), "\x1b[1;38;5;203;48;5;236m");
+        }
+
+        #[test]
+        fn from_sgr_spec_parses_truecolor() {
+            let style = Style::from_sgr_spec("4;38;2;10;20;30").unwrap();
+            assert_eq!(style.to_ansi(), "\x1b[4;38;2;10;20;30m");
+        }
+
+        #[test]
+        fn from_sgr_spec_zero_resets_to_default() {
+            let style = Style::from_sgr_spec("0").unwrap();
+            assert_eq!(style.to_ansi(), "\x1b[m");
+        }
+
+        #[test]
+        fn from_sgr_spec_rejects_unsupported_code() {
+            assert!(Style::from_sgr_spec("9").is_err());
+        }
+
+        #[test]
+        fn from_sgr_spec_rejects_incomplete_color() {
+            assert!(Style::from_sgr_spec("38").is_err());
+        }
+    }
 }
 
 pub mod arg_parser {
@@ -65,11 +426,54 @@ pub mod arg_parser {
         #[arg(short, long)]
         pub ignore_case: bool,
 
+        #[arg(
+            short = 'S',
+            long,
+            help = "Match case-insensitively unless the keyword contains an uppercase character"
+        )]
+        pub smart_case: bool,
+
         #[arg(short, long, help = "Path to the input file (defaults to stdin)")]
         pub file: Option<PathBuf>,
 
+        #[arg(
+            long,
+            help = "Force the legacy Windows console backend (SetConsoleTextAttribute) instead of raw ANSI"
+        )]
+        pub legacy_console: bool,
+
+        #[arg(
+            long,
+            help = "Input text encoding (e.g. shift_jis, utf-16le). Defaults to BOM sniffing, then UTF-8"
+        )]
+        pub encoding: Option<String>,
+
+        #[arg(
+            long,
+            value_enum,
+            help = "Downgrade output colors for terminals with limited color support \
+                    (default: auto-detected from COLORTERM/TERM)"
+        )]
+        pub color_mode: Option<crate::rules::ColorMode>,
+
         #[arg(short, long, help = "Path to the YAML config file (required)")]
         pub config: Option<PathBuf>,
+
+        #[arg(long, help = "Print only lines that triggered at least one rule")]
+        pub only_matching: bool,
+
+        #[arg(long, help = "Print only lines that matched no rule")]
+        pub invert: bool,
+
+        #[arg(short = 'n', long, help = "Prefix each printed line with its line number")]
+        pub line_number: bool,
+
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Also print N lines of context around each match (like grep -C)"
+        )]
+        pub context: usize,
     }
 
     #[derive(Debug, Deserialize)]
@@ -118,12 +522,101 @@ pub mod highlight {
     pub struct HighlightingEngine {
         regex: crate::Regex,
         ansi_colors: Vec<String>,
+        /// Per-rule sub-group coloring, parallel to `ansi_colors`. Empty
+        /// for a rule means "color the whole match" (the original
+        /// behavior); otherwise each entry is a capture group to color
+        /// individually instead.
+        sub_groups: Vec<Vec<(ResolvedGroup, String)>>,
+    }
+
+    /// A rule's `group`/`groups` config resolved to something `Captures`
+    /// can look up directly: a name, or an absolute index into the
+    /// *combined* regex (accounting for the rule's own wrapping group and
+    /// every capturing group contributed by earlier rules).
+    enum ResolvedGroup {
+        Index(usize),
+        Name(String),
+    }
+
+    /// Mirrors fd's smart-case heuristic: a keyword is considered to have
+    /// "real" uppercase content only when that uppercase character isn't
+    /// buried inside a regex escape (`\P{Lu}`) or character class, since
+    /// those don't represent a literal uppercase letter the user typed.
+    fn pattern_has_uppercase_char(pattern: &str, is_regex: bool) -> bool {
+        if !is_regex {
+            return pattern.chars().any(|c| c.is_uppercase());
+        }
+
+        let mut chars = pattern.chars().peekable();
+        let mut in_class = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    // Skip the whole escape: the escaped character plus any
+                    // braced argument (`\P{Lu}`, `\x{1F}`), one-letter
+                    // Unicode class (`\pL`) or two-digit hex code (`\xFF`).
+                    // None of it is a literal uppercase letter, so it
+                    // shouldn't drive smart-case.
+                    match chars.next() {
+                        Some(_) if chars.peek() == Some(&'{') => {
+                            for c in chars.by_ref() {
+                                if c == '}' {
+                                    break;
+                                }
+                            }
+                        }
+                        Some('p' | 'P') => {
+                            chars.next();
+                        }
+                        Some('x') => {
+                            chars.nth(1);
+                        }
+                        _ => {}
+                    }
+                }
+                '[' if !in_class => in_class = true,
+                ']' if in_class => in_class = false,
+                '(' if !in_class && chars.peek() == Some(&'?') => {
+                    // Skip a `(?P<Name>`/`(?<Name>` group-name prefix or a
+                    // `(?i)`/`(?i:` inline-flag prefix up through its
+                    // closing `>`, `:`, or `)`: none of it is literal
+                    // pattern text, so it shouldn't drive smart-case even
+                    // when a group is named e.g. `Status`.
+                    for c in chars.by_ref() {
+                        if c == '>' || c == ':' || c == ')' {
+                            break;
+                        }
+                    }
+                }
+                c if !in_class && c.is_uppercase() => return true,
+                _ => {}
+            }
+        }
+        false
     }
 
     impl HighlightingEngine {
-        pub fn new(rules: &[crate::rules::Rule], ignore_case: bool) -> anyhow::Result<Self> {
+        pub fn new(
+            rules: &[crate::rules::Rule],
+            ignore_case: bool,
+            smart_case: bool,
+            color_mode: crate::rules::ColorMode,
+        ) -> anyhow::Result<Self> {
             let mut patterns = Vec::with_capacity(rules.len());
             let mut ansi_colors = Vec::with_capacity(rules.len());
+            let mut sub_groups = Vec::with_capacity(rules.len());
+            // Running count of capturing groups contributed by earlier
+            // rules, so a rule's `group`/`groups` indices (which the user
+            // writes relative to their own pattern) can be translated into
+            // absolute indices into the combined alternation.
+            let mut group_offset = 0usize;
+            // Every `(?P<name>...)` the rules contribute, used to reject
+            // collisions up front: the combined alternation concatenates
+            // all rules' capturing groups into one regex, and the `regex`
+            // crate refuses to compile two capture groups with the same
+            // name, so a clash in rule N would otherwise fail the whole
+            // engine with an error that doesn't say which rule caused it.
+            let mut seen_group_names = std::collections::HashSet::new();
 
             for (i, rule) in rules.iter().enumerate() {
                 let pat = if rule.is_regex {
@@ -131,13 +624,72 @@ pub mod highlight {
                 } else {
                     regex::escape(&rule.keyword)
                 };
+
                 // 使用命名捕获组 rN 以便匹配后快速索引颜色
-                patterns.push(format!(r"(?P<r{}>{})", i, pat));
-                ansi_colors.push(rule.color.to_ansi());
+                let rule_is_insensitive = if smart_case {
+                    !pattern_has_uppercase_char(&rule.keyword, rule.is_regex)
+                } else {
+                    ignore_case
+                };
+                let group = if rule_is_insensitive {
+                    format!(r"(?P<r{}>(?i:{}))", i, pat)
+                } else {
+                    format!(r"(?P<r{}>{})", i, pat)
+                };
+                patterns.push(group);
+                ansi_colors.push(rule.style.downgraded(color_mode).to_ansi());
+
+                // The rule's own pattern may itself contain capturing
+                // groups; compiling it standalone is the simplest way to
+                // learn how many (and which are named) without hand-
+                // parsing the regex syntax.
+                let own_regex = crate::Regex::new(&pat).ok();
+                let own_group_count = own_regex
+                    .as_ref()
+                    .map(|re| re.captures_len() - 1)
+                    .unwrap_or(0);
+                for name in own_regex.iter().flat_map(|re| re.capture_names().flatten()) {
+                    if !seen_group_names.insert(name.to_string()) {
+                        anyhow::bail!(
+                            "rule {i} (keyword {:?}) reuses capture group name {name:?}, \
+                             which an earlier rule already defines; group names must be \
+                             unique across all rules",
+                            rule.keyword
+                        );
+                    }
+                }
+                let wrapper_index = group_offset + 1;
+
+                let configured: Vec<(crate::rules::GroupRef, &crate::rules::Style)> =
+                    if !rule.groups.is_empty() {
+                        rule.groups
+                            .iter()
+                            .map(|g| (g.group.clone(), &g.style))
+                            .collect()
+                    } else if let Some(group_ref) = &rule.group {
+                        vec![(group_ref.clone(), &rule.style)]
+                    } else {
+                        Vec::new()
+                    };
+
+                let resolved = configured
+                    .into_iter()
+                    .map(|(group_ref, style)| {
+                        let resolved_group = match group_ref {
+                            crate::rules::GroupRef::Index(j) => {
+                                ResolvedGroup::Index(wrapper_index + j)
+                            }
+                            crate::rules::GroupRef::Name(name) => ResolvedGroup::Name(name),
+                        };
+                        (resolved_group, style.downgraded(color_mode).to_ansi())
+                    })
+                    .collect();
+                sub_groups.push(resolved);
+
+                group_offset += 1 + own_group_count;
             }
 
             let combined_re = crate::RegexBuilder::new(&patterns.join("|"))
-                .case_insensitive(ignore_case)
                 .multi_line(true)
                 .dot_matches_new_line(false)
                 .build()?;
@@ -145,35 +697,661 @@ pub mod highlight {
             Ok(Self {
                 regex: combined_re,
                 ansi_colors,
+                sub_groups,
             })
         }
 
-        pub fn render_line(&self, input: &str, output: &mut String) {
+        /// Renders `input` into `output` with matches colored, returning
+        /// whether any rule fired so callers can drive `--only-matching`,
+        /// `--invert`, and `--context` without re-scanning the line.
+        pub fn render_line(&self, input: &str, output: &mut String) -> bool {
             output.clear();
+
+            // An empty ruleset joins to the empty pattern `""`, which
+            // matches the empty string at every position instead of not
+            // matching at all; fall back to plain passthrough rather than
+            // iterating matches that belong to no rule.
+            if self.ansi_colors.is_empty() {
+                output.push_str(input);
+                return false;
+            }
+
             let mut last_match = 0;
+            let mut matched = false;
 
             for caps in self.regex.captures_iter(input) {
                 let whole_match = caps.get(0).unwrap();
+                matched = true;
 
                 // 写入匹配项之前的文本
                 output.push_str(&input[last_match..whole_match.start()]);
 
                 // 寻找是哪个规则触发了匹配
-                for (i, color_code) in self.ansi_colors.iter().enumerate() {
-                    if let Some(m) = caps.name(&format!("r{}", i)) {
-                        output.push_str(color_code);
-                        output.push_str(m.as_str());
-                        output.push_str("\x1b[0m");
+                let mut rule_idx = None;
+                for i in 0..self.ansi_colors.len() {
+                    if caps.name(&format!("r{}", i)).is_some() {
+                        rule_idx = Some(i);
                         break;
                     }
                 }
+                let rule_idx = rule_idx.expect("combined regex match always belongs to a rule");
+                let sub_groups = &self.sub_groups[rule_idx];
+
+                if sub_groups.is_empty() {
+                    output.push_str(&self.ansi_colors[rule_idx]);
+                    output.push_str(whole_match.as_str());
+                    output.push_str("\x1b[0m");
+                } else {
+                    // Color only the configured capture groups, leaving the
+                    // rest of the match as plain text. Groups that didn't
+                    // participate (e.g. an unmatched alternative) are
+                    // skipped, and overlapping groups keep whichever one
+                    // sorts first (the outermost, on a tie the earliest).
+                    let mut spans: Vec<(usize, usize, &str)> = sub_groups
+                        .iter()
+                        .filter_map(|(group, ansi)| {
+                            let m = match group {
+                                ResolvedGroup::Index(idx) => caps.get(*idx),
+                                ResolvedGroup::Name(name) => caps.name(name),
+                            }?;
+                            Some((m.start(), m.end(), ansi.as_str()))
+                        })
+                        .collect();
+                    spans.sort_by_key(|&(start, end, _)| (start, std::cmp::Reverse(end)));
+
+                    let mut cursor = whole_match.start();
+                    for (start, end, ansi) in spans {
+                        if start < cursor {
+                            continue;
+                        }
+                        output.push_str(&input[cursor..start]);
+                        output.push_str(ansi);
+                        output.push_str(&input[start..end]);
+                        output.push_str("\x1b[0m");
+                        cursor = end;
+                    }
+                    output.push_str(&input[cursor..whole_match.end()]);
+                }
+
                 last_match = whole_match.end();
             }
             // 写入剩余文本
             output.push_str(&input[last_match..]);
+            matched
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::rules::{Color, ColorMode, GroupRef, GroupStyle, PresetColor, Rule, Style};
+
+        #[test]
+        fn group_offsets_account_for_earlier_rules_own_capture_groups() {
+            let red = Style {
+                fg: Some(Color::Preset(PresetColor::Red)),
+                ..Style::default()
+            };
+            let blue = Style {
+                fg: Some(Color::Preset(PresetColor::Blue)),
+                ..Style::default()
+            };
+
+            let rules = vec![
+                Rule {
+                    keyword: r"(a)(b)".to_string(),
+                    style: red.clone(),
+                    is_regex: true,
+                    group: None,
+                    groups: vec![],
+                },
+                Rule {
+                    keyword: r"(c)(d)".to_string(),
+                    style: Style::default(),
+                    is_regex: true,
+                    group: None,
+                    // Index 2 is this rule's *own* second capture group
+                    // ("d"), not an absolute index into the combined
+                    // regex; `HighlightingEngine::new` must offset it past
+                    // rule 0's wrapper and its two own groups.
+                    groups: vec![GroupStyle {
+                        group: GroupRef::Index(2),
+                        style: blue.clone(),
+                    }],
+                },
+            ];
+
+            let engine =
+                HighlightingEngine::new(&rules, false, false, ColorMode::Truecolor).unwrap();
+
+            let mut out = String::new();
+            let matched = engine.render_line("abcd", &mut out);
+
+            assert!(matched);
+            assert_eq!(
+                out,
+                format!("{}ab\x1b[0mc{}d\x1b[0m", red.to_ansi(), blue.to_ansi())
+            );
+        }
+
+        #[test]
+        fn duplicate_capture_group_names_across_rules_are_rejected() {
+            let rules = vec![
+                Rule {
+                    keyword: r"(?P<dup>a)".to_string(),
+                    style: Style::default(),
+                    is_regex: true,
+                    group: None,
+                    groups: vec![],
+                },
+                Rule {
+                    keyword: r"(?P<dup>b)".to_string(),
+                    style: Style::default(),
+                    is_regex: true,
+                    group: None,
+                    groups: vec![],
+                },
+            ];
+
+            let result = HighlightingEngine::new(&rules, false, false, ColorMode::Truecolor);
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn empty_ruleset_passes_lines_through_unmodified() {
+            let engine =
+                HighlightingEngine::new(&[], false, false, ColorMode::Truecolor).unwrap();
+
+            let mut out = String::new();
+            let matched = engine.render_line("hello world", &mut out);
+
+            assert!(!matched);
+            assert_eq!(out, "hello world");
         }
     }
 }
+// --- Output backends ---
+pub mod backend {
+    //! `render_line` always emits `\x1b[...m` ANSI escapes. That's fine on
+    //! every VT-capable terminal, but older Windows consoles without VT
+    //! processing print the raw escape bytes as garbage. `ConsoleWriter`
+    //! wraps the real output so that on such consoles we parse our own
+    //! escapes back out and replay them as `SetConsoleTextAttribute` calls
+    //! instead.
+    use std::io::{self, Write};
+
+    /// Picks between passthrough and the legacy translation backend and
+    /// forwards all writes to whichever is active.
+    pub enum ConsoleWriter<W: Write> {
+        Ansi(W),
+        #[cfg(windows)]
+        Legacy(LegacyConsole<W>),
+    }
+
+    impl<W: Write> ConsoleWriter<W> {
+        /// Uses the legacy backend when `force` is set, or, on Windows,
+        /// when stdout's console mode doesn't already support (or accept)
+        /// `ENABLE_VIRTUAL_TERMINAL_PROCESSING`. Everywhere else this is
+        /// always plain passthrough.
+        pub fn new(writer: W, force: bool) -> io::Result<Self> {
+            #[cfg(windows)]
+            {
+                if force || !console_supports_vt() {
+                    return Ok(ConsoleWriter::Legacy(LegacyConsole::new(writer)?));
+                }
+            }
+            #[cfg(not(windows))]
+            let _ = force;
+
+            Ok(ConsoleWriter::Ansi(writer))
+        }
+    }
+
+    impl<W: Write> Write for ConsoleWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self {
+                ConsoleWriter::Ansi(w) => w.write(buf),
+                #[cfg(windows)]
+                ConsoleWriter::Legacy(w) => w.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            match self {
+                ConsoleWriter::Ansi(w) => w.flush(),
+                #[cfg(windows)]
+                ConsoleWriter::Legacy(w) => w.flush(),
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    fn console_supports_vt() -> bool {
+        use windows_sys::Win32::System::Console::{
+            GetConsoleMode, GetStdHandle, SetConsoleMode, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+            STD_OUTPUT_HANDLE,
+        };
+
+        unsafe {
+            let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+            let mut mode = 0;
+            if GetConsoleMode(handle, &mut mode) == 0 {
+                // Not a console at all (redirected to a file or pipe):
+                // there is no console to translate for, so keep passthrough.
+                return true;
+            }
+            // Opting in succeeds on modern Windows Terminal / conhost
+            // builds; if it's rejected we're on a genuinely legacy console.
+            SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0
+        }
+    }
+
+    /// One step of translating a `\x1b[...m` escape into a console action.
+    #[cfg(windows)]
+    enum SgrAction {
+        Set(u16),
+        Reset,
+    }
+
+    /// Parses the SGR sequences the renderer can emit: a plain reset
+    /// (`\x1b[0m`), and otherwise a `;`-separated parameter list combining
+    /// any of intensity (`1`), the 16-color foreground/background sets
+    /// (`3{0-7}`/`4{0-7}`), 256-color indices (`38;5;N`/`48;5;N`), and
+    /// truecolor (`38;2;r;g;b`/`48;2;r;g;b`) — e.g.
+    /// `\x1b[1;38;5;203;48;5;236m`. Returns the action plus how many bytes
+    /// of `input` it consumed.
+    #[cfg(windows)]
+    fn parse_sgr_escape(input: &str) -> Option<(SgrAction, usize)> {
+        let body = input.strip_prefix("\x1b[")?;
+        let end = body.find('m')?;
+        let params = &body[..end];
+        let consumed = 2 + end + 1;
+
+        if params == "0" {
+            return Some((SgrAction::Reset, consumed));
+        }
+
+        Some((SgrAction::Set(parse_sgr_attrs(params)), consumed))
+    }
+
+    /// Combines every attribute in a `;`-separated SGR parameter list into
+    /// a single console attribute mask. Attributes with no console
+    /// equivalent (italic, underline, reverse, dim, ...) are silently
+    /// skipped, matching how the parser as a whole favors a best-effort
+    /// translation over dropping the whole escape.
+    #[cfg(windows)]
+    fn parse_sgr_attrs(params: &str) -> u16 {
+        use windows_sys::Win32::System::Console::FOREGROUND_INTENSITY;
+
+        let mut attrs = 0u16;
+        let mut parts = params.split(';').peekable();
+
+        while let Some(code) = parts.next() {
+            match code {
+                "1" => attrs |= FOREGROUND_INTENSITY,
+                "38" | "48" => {
+                    let is_bg = code == "48";
+                    if let Some((r, g, b)) = parse_color_tail(&mut parts) {
+                        attrs |= if is_bg {
+                            rgb_to_console_bg_attr(r, g, b)
+                        } else {
+                            rgb_to_console_attr(r, g, b)
+                        };
+                    }
+                }
+                code if code.len() == 2 && code.starts_with('3') => {
+                    if let Ok(n) = code[1..].parse::<u8>() {
+                        if n <= 7 {
+                            attrs |= basic_color_attr(n);
+                        }
+                    }
+                }
+                code if code.len() == 2 && code.starts_with('4') => {
+                    if let Ok(n) = code[1..].parse::<u8>() {
+                        if n <= 7 {
+                            attrs |= basic_color_bg_attr(n);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        attrs
+    }
+
+    /// Consumes a `5;N` (256-color) or `2;r;g;b` (truecolor) tail after a
+    /// `38`/`48` code, resolving a 256-color index to RGB the same way the
+    /// renderer's own downgrade path does.
+    #[cfg(windows)]
+    fn parse_color_tail<'a>(parts: &mut impl Iterator<Item = &'a str>) -> Option<(u8, u8, u8)> {
+        match parts.next()? {
+            "5" => {
+                let n: u8 = parts.next()?.parse().ok()?;
+                Some(crate::rules::ansi256_to_rgb(n))
+            }
+            "2" => {
+                let r = parts.next()?.parse().ok()?;
+                let g = parts.next()?.parse().ok()?;
+                let b = parts.next()?.parse().ok()?;
+                Some((r, g, b))
+            }
+            _ => None,
+        }
+    }
+
+    /// Maps an ANSI `3N` color index to the matching Windows console
+    /// foreground bits (no bold/intensity; intensity is carried
+    /// separately by the `1` SGR code).
+    #[cfg(windows)]
+    fn basic_color_attr(n: u8) -> u16 {
+        use windows_sys::Win32::System::Console::{
+            FOREGROUND_BLUE, FOREGROUND_GREEN, FOREGROUND_RED,
+        };
+
+        match n {
+            0 => 0,
+            1 => FOREGROUND_RED,
+            2 => FOREGROUND_GREEN,
+            3 => FOREGROUND_RED | FOREGROUND_GREEN,
+            4 => FOREGROUND_BLUE,
+            5 => FOREGROUND_RED | FOREGROUND_BLUE,
+            6 => FOREGROUND_GREEN | FOREGROUND_BLUE,
+            _ => FOREGROUND_RED | FOREGROUND_GREEN | FOREGROUND_BLUE,
+        }
+    }
+
+    /// Background counterpart of `basic_color_attr`, for the ANSI `4N`
+    /// codes.
+    #[cfg(windows)]
+    fn basic_color_bg_attr(n: u8) -> u16 {
+        use windows_sys::Win32::System::Console::{
+            BACKGROUND_BLUE, BACKGROUND_GREEN, BACKGROUND_RED,
+        };
+
+        match n {
+            0 => 0,
+            1 => BACKGROUND_RED,
+            2 => BACKGROUND_GREEN,
+            3 => BACKGROUND_RED | BACKGROUND_GREEN,
+            4 => BACKGROUND_BLUE,
+            5 => BACKGROUND_RED | BACKGROUND_BLUE,
+            6 => BACKGROUND_GREEN | BACKGROUND_BLUE,
+            _ => BACKGROUND_RED | BACKGROUND_GREEN | BACKGROUND_BLUE,
+        }
+    }
+
+    /// Truecolor has no exact Windows console equivalent, so each channel
+    /// is thresholded at 128 to pick one of the 8 basic foreground
+    /// combinations, and the intensity bit is set when any channel is above
+    /// 200. This is a per-channel cut, not a nearest-color search over the
+    /// 16 console colors: e.g. `(100, 100, 100)` maps to black, not gray.
+    #[cfg(windows)]
+    fn rgb_to_console_attr(r: u8, g: u8, b: u8) -> u16 {
+        use windows_sys::Win32::System::Console::{
+            FOREGROUND_BLUE, FOREGROUND_GREEN, FOREGROUND_INTENSITY, FOREGROUND_RED,
+        };
+
+        const THRESHOLD: u8 = 128;
+        let mut attr = 0u16;
+        if r >= THRESHOLD {
+            attr |= FOREGROUND_RED;
+        }
+        if g >= THRESHOLD {
+            attr |= FOREGROUND_GREEN;
+        }
+        if b >= THRESHOLD {
+            attr |= FOREGROUND_BLUE;
+        }
+        if r.max(g).max(b) > 200 {
+            attr |= FOREGROUND_INTENSITY;
+        }
+        attr
+    }
+
+    /// Background counterpart of `rgb_to_console_attr`, for `48;2`/`48;5`.
+    #[cfg(windows)]
+    fn rgb_to_console_bg_attr(r: u8, g: u8, b: u8) -> u16 {
+        use windows_sys::Win32::System::Console::{
+            BACKGROUND_BLUE, BACKGROUND_GREEN, BACKGROUND_INTENSITY, BACKGROUND_RED,
+        };
+
+        const THRESHOLD: u8 = 128;
+        let mut attr = 0u16;
+        if r >= THRESHOLD {
+            attr |= BACKGROUND_RED;
+        }
+        if g >= THRESHOLD {
+            attr |= BACKGROUND_GREEN;
+        }
+        if b >= THRESHOLD {
+            attr |= BACKGROUND_BLUE;
+        }
+        if r.max(g).max(b) > 200 {
+            attr |= BACKGROUND_INTENSITY;
+        }
+        attr
+    }
+
+    #[cfg(windows)]
+    pub struct LegacyConsole<W: Write> {
+        inner: W,
+        handle: windows_sys::Win32::Foundation::HANDLE,
+        default_attributes: u16,
+    }
+
+    #[cfg(windows)]
+    impl<W: Write> LegacyConsole<W> {
+        pub fn new(inner: W) -> io::Result<Self> {
+            use windows_sys::Win32::System::Console::{
+                GetConsoleScreenBufferInfo, GetStdHandle, CONSOLE_SCREEN_BUFFER_INFO,
+                STD_OUTPUT_HANDLE,
+            };
+
+            unsafe {
+                let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+                let mut info: CONSOLE_SCREEN_BUFFER_INFO = std::mem::zeroed();
+                if GetConsoleScreenBufferInfo(handle, &mut info) == 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Self {
+                    inner,
+                    handle,
+                    default_attributes: info.wAttributes,
+                })
+            }
+        }
+
+        fn write_plain(&mut self, text: &str) -> io::Result<()> {
+            if !text.is_empty() {
+                self.inner.write_all(text.as_bytes())?;
+            }
+            Ok(())
+        }
+
+        fn set_attributes(&self, attrs: u16) {
+            use windows_sys::Win32::System::Console::SetConsoleTextAttribute;
+            unsafe {
+                SetConsoleTextAttribute(self.handle, attrs);
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    impl<W: Write> Write for LegacyConsole<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            // `render_line` only ever hands us valid UTF-8 lines, so this
+            // loses nothing in practice; a stray invalid chunk just falls
+            // back to passthrough for that write.
+            let Ok(text) = std::str::from_utf8(buf) else {
+                return self.inner.write(buf);
+            };
+
+            let mut rest = text;
+            while let Some(start) = rest.find('\x1b') {
+                self.write_plain(&rest[..start])?;
+                rest = &rest[start..];
+
+                match parse_sgr_escape(rest) {
+                    Some((SgrAction::Set(attrs), consumed)) => {
+                        self.set_attributes(attrs);
+                        rest = &rest[consumed..];
+                    }
+                    Some((SgrAction::Reset, consumed)) => {
+                        self.set_attributes(self.default_attributes);
+                        rest = &rest[consumed..];
+                    }
+                    None => rest = &rest[1..],
+                }
+            }
+            self.write_plain(rest)?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+}
+
+// --- Input transcoding ---
+pub mod encoding {
+    //! `process_stream` reads lines into a `String`, so any input that
+    //! isn't valid UTF-8 (UTF-16 with a BOM, Latin-1, Shift-JIS, ...) would
+    //! otherwise fail outright. This module sits in front of the raw byte
+    //! stream and transcodes it to UTF-8 on the fly with `encoding_rs`.
+    use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+    use std::io::{self, Read};
+
+    /// Resolves the encoding to use: an explicit `--encoding` label wins,
+    /// otherwise a leading BOM is sniffed, otherwise UTF-8 (with lossy
+    /// replacement of invalid bytes) is assumed.
+    pub fn resolve_encoding(label: Option<&str>, leading_bytes: &[u8]) -> &'static Encoding {
+        if let Some(encoding) = label.and_then(|label| Encoding::for_label(label.as_bytes())) {
+            return encoding;
+        }
+        sniff_bom(leading_bytes).unwrap_or(UTF_8)
+    }
+
+    fn sniff_bom(bytes: &[u8]) -> Option<&'static Encoding> {
+        if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+            Some(UTF_8)
+        } else if bytes.starts_with(&[0xFF, 0xFE]) {
+            Some(UTF_16LE)
+        } else if bytes.starts_with(&[0xFE, 0xFF]) {
+            Some(UTF_16BE)
+        } else {
+            None
+        }
+    }
+
+    /// Transcodes an arbitrary byte stream to UTF-8 using `encoding_rs`'s
+    /// streaming decoder, so we never have to buffer the whole input.
+    /// Malformed sequences are replaced rather than treated as an error,
+    /// matching `encoding_rs`'s standard lossy decoding behavior.
+    pub struct TranscodingReader<R> {
+        inner: R,
+        decoder: encoding_rs::Decoder,
+        in_buf: [u8; 4096],
+        out_buf: String,
+        out_pos: usize,
+        reached_eof: bool,
+    }
+
+    impl<R: Read> TranscodingReader<R> {
+        pub fn new(inner: R, encoding: &'static Encoding) -> Self {
+            Self {
+                inner,
+                decoder: encoding.new_decoder(),
+                in_buf: [0; 4096],
+                out_buf: String::new(),
+                out_pos: 0,
+                reached_eof: false,
+            }
+        }
+
+        fn refill(&mut self) -> io::Result<()> {
+            let n = self.inner.read(&mut self.in_buf)?;
+            self.reached_eof = n == 0;
+            self.out_buf.clear();
+            self.out_pos = 0;
+            // `decode_to_string` writes into `out_buf`'s existing spare
+            // capacity rather than growing it itself, so we have to
+            // reserve enough room for the worst case up front.
+            let needed = self
+                .decoder
+                .max_utf8_buffer_length(n)
+                .unwrap_or(n.saturating_mul(3));
+            self.out_buf.reserve(needed);
+            let _ =
+                self.decoder
+                    .decode_to_string(&self.in_buf[..n], &mut self.out_buf, self.reached_eof);
+            Ok(())
+        }
+    }
+
+    impl<R: Read> Read for TranscodingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.out_pos >= self.out_buf.len() && !self.reached_eof {
+                self.refill()?;
+            }
+            let remaining = &self.out_buf.as_bytes()[self.out_pos..];
+            let to_copy = remaining.len().min(buf.len());
+            buf[..to_copy].copy_from_slice(&remaining[..to_copy]);
+            self.out_pos += to_copy;
+            Ok(to_copy)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Read;
+
+        #[test]
+        fn resolve_encoding_prefers_explicit_label_over_bom() {
+            let utf16_bom = [0xFF, 0xFE];
+            let resolved = resolve_encoding(Some("utf-8"), &utf16_bom);
+            assert_eq!(resolved, UTF_8);
+        }
+
+        #[test]
+        fn resolve_encoding_sniffs_utf8_bom() {
+            let bytes = [0xEF, 0xBB, 0xBF, b'h', b'i'];
+            assert_eq!(resolve_encoding(None, &bytes), UTF_8);
+        }
+
+        #[test]
+        fn resolve_encoding_sniffs_utf16le_bom() {
+            let bytes = [0xFF, 0xFE, b'h', 0, b'i', 0];
+            assert_eq!(resolve_encoding(None, &bytes), UTF_16LE);
+        }
+
+        #[test]
+        fn resolve_encoding_sniffs_utf16be_bom() {
+            let bytes = [0xFE, 0xFF, 0, b'h', 0, b'i'];
+            assert_eq!(resolve_encoding(None, &bytes), UTF_16BE);
+        }
+
+        #[test]
+        fn resolve_encoding_defaults_to_utf8_without_bom_or_label() {
+            let bytes = b"plain ascii text";
+            assert_eq!(resolve_encoding(None, bytes), UTF_8);
+        }
+
+        #[test]
+        fn transcoding_reader_decodes_utf16le_to_utf8() {
+            // "hi" encoded as UTF-16LE, no BOM (the BOM is only used for
+            // sniffing; `TranscodingReader` itself just decodes).
+            let utf16le_hi = [b'h', 0, b'i', 0];
+            let mut reader = TranscodingReader::new(&utf16le_hi[..], UTF_16LE);
+            let mut out = String::new();
+            reader.read_to_string(&mut out).unwrap();
+            assert_eq!(out, "hi");
+        }
+    }
+}
+
 // --- Main Logic ---
 
 pub fn run(cli_args: arg_parser::CliArgs) -> anyhow::Result<()> {
@@ -183,40 +1361,237 @@ pub fn run(cli_args: arg_parser::CliArgs) -> anyhow::Result<()> {
     let raw_rules = arg_parser::load_rules_from_file(&config_path)?;
 
     // 1. 预编译引擎
-    let engine = highlight::HighlightingEngine::new(&raw_rules, cli_args.ignore_case)?;
+    let color_mode = cli_args
+        .color_mode
+        .unwrap_or_else(rules::detect_color_mode_from_env);
+    let engine = highlight::HighlightingEngine::new(
+        &raw_rules,
+        cli_args.ignore_case,
+        cli_args.smart_case,
+        color_mode,
+    )?;
 
     // 2. 准备带缓冲的输出
     let stdout = std::io::stdout();
-    let mut writer = BufWriter::new(stdout.lock());
+    let console_writer = backend::ConsoleWriter::new(stdout.lock(), cli_args.legacy_console)?;
+    let mut writer = BufWriter::new(console_writer);
 
     // 3. 处理输入
-    if let Some(path) = cli_args.file {
-        let f = fs::File::open(path)?;
-        process_stream(BufReader::new(f), &engine, &mut writer)?;
+    let mut raw_reader: Box<dyn BufRead> = if let Some(path) = cli_args.file {
+        Box::new(BufReader::new(fs::File::open(path)?))
     } else {
         if std::io::stdin().is_terminal() {
             eprintln!("(Info: Waiting for stdin... Press Ctrl+D to end)");
         }
-        process_stream(BufReader::new(std::io::stdin()), &engine, &mut writer)?;
-    }
+        Box::new(BufReader::new(std::io::stdin()))
+    };
+
+    let encoding = {
+        let leading_bytes = raw_reader.fill_buf()?;
+        encoding::resolve_encoding(cli_args.encoding.as_deref(), leading_bytes)
+    };
+    let reader = BufReader::new(encoding::TranscodingReader::new(raw_reader, encoding));
+
+    let display = DisplayOptions {
+        only_matching: cli_args.only_matching,
+        invert: cli_args.invert,
+        line_number: cli_args.line_number,
+        context: cli_args.context,
+    };
+    process_stream(reader, &engine, &mut writer, &display)?;
 
     writer.flush()?;
     Ok(())
 }
 
+/// Grep-style filtering/display knobs layered on top of the plain
+/// colorizer: which lines are kept, whether they're numbered, and how
+/// much surrounding context to pull in around a kept line.
+struct DisplayOptions {
+    only_matching: bool,
+    invert: bool,
+    line_number: bool,
+    context: usize,
+}
+
+impl DisplayOptions {
+    /// Whether `matched` makes this line one we want to keep (and, when
+    /// `context` is set, a line to pull surrounding context around).
+    fn selects(&self, matched: bool) -> bool {
+        if self.invert {
+            !matched
+        } else if self.only_matching {
+            matched
+        } else {
+            true
+        }
+    }
+}
+
 fn process_stream<R: BufRead, W: Write>(
     mut reader: R,
     engine: &highlight::HighlightingEngine,
     writer: &mut W,
+    display: &DisplayOptions,
 ) -> anyhow::Result<()> {
     let mut line_buffer = String::new();
     let mut out_buffer = String::new();
+    let mut line_no: u64 = 0;
+    let mut before_context: std::collections::VecDeque<(u64, String)> =
+        std::collections::VecDeque::with_capacity(display.context);
+    let mut after_remaining: usize = 0;
+    let mut last_printed: Option<u64> = None;
 
     // 循环复用 String 内存，避免每行都分配内存
     while reader.read_line(&mut line_buffer)? > 0 {
-        engine.render_line(&line_buffer, &mut out_buffer);
-        writer.write_all(out_buffer.as_bytes())?;
+        line_no += 1;
+        let matched = engine.render_line(&line_buffer, &mut out_buffer);
+
+        if display.selects(matched) {
+            if display.context > 0 {
+                let first_buffered = before_context.front().map(|(no, _)| *no);
+                write_separator_if_gapped(writer, last_printed, first_buffered.unwrap_or(line_no))?;
+            }
+            for (no, rendered) in before_context.drain(..) {
+                write_line(writer, display, no, &rendered)?;
+            }
+            write_line(writer, display, line_no, &out_buffer)?;
+            last_printed = Some(line_no);
+            after_remaining = display.context;
+        } else if after_remaining > 0 {
+            write_line(writer, display, line_no, &out_buffer)?;
+            last_printed = Some(line_no);
+            after_remaining -= 1;
+        } else if display.context > 0 {
+            before_context.push_back((line_no, out_buffer.clone()));
+            if before_context.len() > display.context {
+                before_context.pop_front();
+            }
+        }
+
         line_buffer.clear();
     }
     Ok(())
 }
+
+fn write_line<W: Write>(
+    writer: &mut W,
+    display: &DisplayOptions,
+    line_no: u64,
+    rendered: &str,
+) -> anyhow::Result<()> {
+    if display.line_number {
+        write!(writer, "{}:", line_no)?;
+    }
+    writer.write_all(rendered.as_bytes())?;
+    Ok(())
+}
+
+/// Mirrors `grep`'s `--` separator: printed between two context/match
+/// blocks only when they aren't adjacent in the input.
+fn write_separator_if_gapped<W: Write>(
+    writer: &mut W,
+    last_printed: Option<u64>,
+    next_line_no: u64,
+) -> anyhow::Result<()> {
+    if last_printed.is_some_and(|last| next_line_no > last + 1) {
+        writeln!(writer, "--")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::{Color, ColorMode, PresetColor, Rule, Style};
+    use std::io::Cursor;
+
+    #[test]
+    fn selects_respects_only_matching_and_invert() {
+        let plain = DisplayOptions {
+            only_matching: false,
+            invert: false,
+            line_number: false,
+            context: 0,
+        };
+        assert!(plain.selects(true));
+        assert!(plain.selects(false));
+
+        let only_matching = DisplayOptions {
+            only_matching: true,
+            ..plain
+        };
+        assert!(only_matching.selects(true));
+        assert!(!only_matching.selects(false));
+
+        let inverted = DisplayOptions {
+            invert: true,
+            ..plain
+        };
+        assert!(!inverted.selects(true));
+        assert!(inverted.selects(false));
+    }
+
+    #[test]
+    fn write_separator_if_gapped_only_between_non_adjacent_blocks() {
+        let mut out = Vec::new();
+        write_separator_if_gapped(&mut out, None, 1).unwrap();
+        assert_eq!(out, b"");
+
+        let mut out = Vec::new();
+        write_separator_if_gapped(&mut out, Some(3), 4).unwrap();
+        assert_eq!(out, b"");
+
+        let mut out = Vec::new();
+        write_separator_if_gapped(&mut out, Some(3), 5).unwrap();
+        assert_eq!(out, b"--\n");
+    }
+
+    fn match_rule_engine() -> highlight::HighlightingEngine {
+        let style = Style {
+            fg: Some(Color::Preset(PresetColor::Red)),
+            ..Style::default()
+        };
+        let rules = vec![Rule {
+            keyword: "match".to_string(),
+            style,
+            is_regex: false,
+            group: None,
+            groups: vec![],
+        }];
+        highlight::HighlightingEngine::new(&rules, false, false, ColorMode::Truecolor).unwrap()
+    }
+
+    /// Exercises `--only-matching` with 1 line of context around two
+    /// separate matches: the before-context buffer is capped to `context`
+    /// lines, trailing context counts down after a match, and the `--`
+    /// separator appears only where the printed blocks actually have a
+    /// gap between them.
+    #[test]
+    fn process_stream_applies_context_and_gap_separator() {
+        let engine = match_rule_engine();
+        let lines = [
+            "a", "b", "c", "match", "d", "e", "f", "g", "match", "h",
+        ];
+        let input = Cursor::new(lines.join("\n").into_bytes());
+        let display = DisplayOptions {
+            only_matching: true,
+            invert: false,
+            line_number: true,
+            context: 1,
+        };
+
+        let mut output = Vec::new();
+        process_stream(BufReader::new(input), &engine, &mut output, &display).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        let red = Style {
+            fg: Some(Color::Preset(PresetColor::Red)),
+            ..Style::default()
+        }
+        .to_ansi();
+        let expected =
+            format!("3:c\n4:{red}match\x1b[0m\n5:d\n--\n8:g\n9:{red}match\x1b[0m\n10:h");
+        assert_eq!(output, expected);
+    }
+}